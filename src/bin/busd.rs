@@ -1,20 +1,35 @@
 extern crate busd;
 
-use std::{fs::File, io::Write, os::fd::FromRawFd, path::PathBuf};
+use std::{
+    fs::File,
+    io::Write,
+    os::fd::{FromRawFd, OwnedFd},
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use busd::{bus, config::Config};
 
 use anyhow::Result;
 use clap::Parser;
+use socket2::Socket;
 use tokio::{process, select, signal::unix::SignalKind};
 use tracing::{error, info, warn};
 
+/// The first file descriptor passed down by the systemd socket-activation protocol.
+///
+/// See `sd_listen_fds(3)`: inherited descriptors are always consecutive, starting here.
+const SD_LISTEN_FDS_START: i32 = 3;
+
 /// A simple D-Bus broker.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// The address to listen on.
-    /// Takes precedence over any `<listen>` element in the configuration file.
+    /// The address(es) to listen on, as a semicolon-separated list.
+    /// Takes precedence over any `<listen>` elements in the configuration file.
     #[clap(short = 'a', long, value_parser)]
     address: Option<String>,
 
@@ -37,6 +52,9 @@ struct Args {
     /// This readiness notification mechanism which works on both systemd and s6.
     ///
     /// This feature is only available on unix-like platforms.
+    ///
+    /// Independent of, and can be combined with, the native systemd notification protocol used
+    /// when `NOTIFY_SOCKET` is set in the environment.
     #[clap(long)]
     ready_fd: Option<i32>,
 
@@ -48,25 +66,265 @@ struct Args {
     /// Equivalent to `--config /usr/share/dbus-1/system.conf`.
     #[clap(long)]
     system: bool,
+
+    /// Adopt listening sockets passed down via systemd socket-activation instead of binding our
+    /// own.
+    ///
+    /// This is implied whenever `LISTEN_FDS` and a `LISTEN_PID` matching our own pid hand down at
+    /// least one socket, so it normally doesn't need to be passed explicitly when started from a
+    /// `.socket` unit.
+    #[clap(long)]
+    systemd: bool,
+
+    /// Exit once the bus has had no connected peers for this many seconds, provided the
+    /// `--command` child (if any) has also already exited.
+    ///
+    /// Makes busd usable as a transient, per-session or per-sandbox bus that tears itself down
+    /// instead of running forever.
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// Exit as soon as the bus is idle and the `--command` child (if any) has exited, with no
+    /// grace period. Equivalent to `--idle-timeout 0`.
+    #[clap(long)]
+    exit_with_session: bool,
+}
+
+/// Adopt any listening sockets passed down via the systemd socket-activation protocol.
+///
+/// Reads the `LISTEN_PID`, `LISTEN_FDS` and optional `LISTEN_FDNAMES` environment variables as
+/// documented in `sd_listen_fds(3)`. `LISTEN_PID` is checked against our own pid so that we don't
+/// accidentally adopt fds meant for a different process further down the exec chain. The
+/// inherited descriptors are consecutive and start at `SD_LISTEN_FDS_START`. All three variables
+/// are removed from the environment once consumed, so any command we spawn doesn't see them too.
+fn systemd_listen_fds() -> Result<Vec<OwnedFd>> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    let listen_fdnames = std::env::var("LISTEN_FDNAMES").ok();
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(Vec::new());
+    };
+
+    let Ok(listen_pid) = listen_pid.parse::<u32>() else {
+        warn!("malformed LISTEN_PID, ignoring LISTEN_FDS");
+        return Ok(Vec::new());
+    };
+    if listen_pid != std::process::id() {
+        warn!("LISTEN_PID does not match our pid, ignoring LISTEN_FDS");
+        return Ok(Vec::new());
+    }
+
+    let Ok(count) = listen_fds.parse::<i32>() else {
+        warn!("malformed LISTEN_FDS, ignoring systemd socket activation");
+        return Ok(Vec::new());
+    };
+    let names: Vec<&str> = listen_fdnames
+        .as_deref()
+        .map(|names| names.split(':').collect())
+        .unwrap_or_default();
+
+    let mut fds = Vec::with_capacity(count.max(0) as usize);
+    for i in 0..count {
+        let name = names.get(i as usize).copied().unwrap_or("unknown");
+        info!(
+            "adopting systemd socket fd {} (\"{}\")",
+            SD_LISTEN_FDS_START + i,
+            name
+        );
+
+        // SAFETY: systemd guarantees that `LISTEN_FDS` consecutive, already bound and listening
+        // file descriptors were passed to us starting at `SD_LISTEN_FDS_START`.
+        let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START + i) };
+        let socket = Socket::from(fd);
+        socket.set_nonblocking(true)?;
+        fds.push(socket.into());
+    }
+
+    Ok(fds)
 }
 
-async fn run_command(command_opt: Option<String>, bus_address: String) -> Result<()> {
+/// A connection to the `sd_notify(3)` service manager notification socket named by the
+/// `NOTIFY_SOCKET` environment variable.
+///
+/// Handles both ordinary filesystem-path sockets and Linux's abstract namespace, where the name
+/// is prefixed with `@` instead of denoting a path.
+struct Notifier {
+    socket: UnixDatagram,
+}
+
+impl Notifier {
+    /// Connects to `NOTIFY_SOCKET`, if set. Returns `None` when we're not running under a
+    /// supervisor that supports the protocol.
+    fn from_env() -> Result<Option<Self>> {
+        let Ok(notify_socket) = std::env::var("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+
+        let socket = UnixDatagram::unbound()?;
+        match notify_socket.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.connect_addr(&addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => anyhow::bail!("abstract NOTIFY_SOCKET names are only supported on Linux"),
+            None => socket.connect(&notify_socket)?,
+        }
+
+        Ok(Some(Self { socket }))
+    }
+
+    /// Sends one or more newline-separated `sd_notify` assignments, e.g. `"READY=1"`.
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send(format!("{state}\n").as_bytes()) {
+            warn!("failed to send sd_notify message: {}", e);
+        }
+    }
+}
+
+/// Spawns a task that sends `WATCHDOG=1` keep-alives at half the `WATCHDOG_USEC` period, if the
+/// service manager asked for watchdog pings.
+fn spawn_watchdog(notifier: Arc<Notifier>) {
+    let Ok(watchdog_usec) = std::env::var("WATCHDOG_USEC") else {
+        return;
+    };
+    let Ok(watchdog_usec) = watchdog_usec.parse::<u64>() else {
+        warn!("ignoring malformed WATCHDOG_USEC");
+        return;
+    };
+
+    let period = Duration::from_micros(watchdog_usec) / 2;
+    if period.is_zero() {
+        warn!("ignoring WATCHDOG_USEC=0, which would trigger an immediate interval panic");
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            notifier.notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Waits for `child` to exit, preferring a pidfd-backed notification (Linux only) over polling
+/// `wait()` directly so that the child is reaped as soon as it's reported dead. Falls back to the
+/// plain `wait()` path when pidfds aren't available (older kernels, or non-Linux platforms).
+#[cfg(target_os = "linux")]
+async fn wait_for_child(child: &mut process::Child) -> Result<std::process::ExitStatus> {
+    let Some(pid) = child.id() else {
+        // Already reaped.
+        return child.wait().await.map_err(Into::into);
+    };
+
+    // SAFETY: `pid` names our own freshly-spawned child; `pidfd_open` is a plain syscall wrapper
+    // and we own the returned fd.
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if pidfd >= 0 {
+        // SAFETY: `pidfd` was just returned to us by a successful `pidfd_open` call above.
+        let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as i32) };
+        if let Ok(async_fd) = tokio::io::unix::AsyncFd::new(pidfd) {
+            let _ = async_fd.readable().await;
+        }
+    }
+
+    child.wait().await.map_err(Into::into)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn wait_for_child(child: &mut process::Child) -> Result<std::process::ExitStatus> {
+    child.wait().await.map_err(Into::into)
+}
+
+/// Maps a child's exit status to a shell-style exit code, turning signal termination into
+/// `128 + signo` so supervisors waiting on busd see the same result they'd see from the child.
+fn exit_code_for_status(status: std::process::ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        return 128 + status.signal().unwrap_or(0);
+    }
+    #[cfg_attr(unix, allow(unreachable_code))]
+    1
+}
+
+/// Splits a `--command` string into a program and its arguments using shell-style (but
+/// shell-free) tokenization, so callers never pass user input through an actual shell.
+fn parse_command_argv(command: &str) -> Result<Vec<String>> {
+    let argv =
+        shlex::split(command).ok_or_else(|| anyhow::anyhow!("invalid command: {}", command))?;
+    if argv.is_empty() {
+        anyhow::bail!("empty command");
+    }
+    Ok(argv)
+}
+
+async fn run_command(
+    command_opt: Option<String>,
+    bus_address: String,
+) -> Result<std::process::ExitStatus> {
     let Some(command) = command_opt else {
         // Simulate never ending command
         std::future::pending().await
     };
-    //TODO: use shlex instead of sh -c?
-    let mut child = process::Command::new("sh")
-        .arg("-c")
-        .arg(command)
+
+    let argv = parse_command_argv(&command)?;
+    let [program, command_args @ ..] = argv.as_slice() else {
+        unreachable!("parse_command_argv never returns an empty argv");
+    };
+
+    let mut child = process::Command::new(program)
+        .args(command_args)
         .env("DBUS_SESSION_BUS_ADDRESS", bus_address)
         .spawn()?;
-    let status = child.wait().await?;
-    //TODO: use exit_status_error when stable
-    if status.success() {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("exit status {}", status))
+
+    wait_for_child(&mut child).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    #[test]
+    fn parse_command_argv_splits_shell_style() {
+        let argv = parse_command_argv("echo 'hello world' --flag").unwrap();
+        assert_eq!(argv, vec!["echo", "hello world", "--flag"]);
+    }
+
+    #[test]
+    fn parse_command_argv_rejects_empty_command() {
+        assert!(parse_command_argv("").is_err());
+        assert!(parse_command_argv("   ").is_err());
+    }
+
+    #[test]
+    fn parse_command_argv_rejects_unbalanced_quotes() {
+        assert!(parse_command_argv("echo 'unterminated").is_err());
+    }
+
+    #[test]
+    fn exit_code_for_status_passes_through_normal_exit() {
+        assert_eq!(exit_code_for_status(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code_for_status(ExitStatus::from_raw(1 << 8)), 1);
+    }
+
+    #[test]
+    fn exit_code_for_status_maps_signals_to_128_plus_signo() {
+        // Low byte encodes the terminating signal (here, SIGTERM = 15); see wait(2).
+        assert_eq!(exit_code_for_status(ExitStatus::from_raw(15)), 128 + 15);
     }
 }
 
@@ -86,13 +344,32 @@ async fn main() -> Result<()> {
     info!("reading configuration file {} ...", config_path.display());
     let config = Config::read_file(&config_path)?;
 
-    let address = if let Some(address) = args.address {
-        Some(address)
+    // `bus::Bus::for_addresses` took over from a single-address `for_address` to support this
+    // list; like `for_systemd_fds` below, its library-side diff can't be shown from this checkout
+    // (no `Cargo.toml`/`src/lib.rs` for the `busd` library crate exists here).
+    let addresses: Vec<String> = if let Some(address) = args.address {
+        // Only `;` separates addresses in a D-Bus address list; `,` separates `key=value` pairs
+        // within a single address (e.g. `tcp:host=127.0.0.1,port=1234`) and must be left alone.
+        address
+            .split(';')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(String::from)
+            .collect()
     } else {
-        config.listen.as_ref().map(ToString::to_string)
+        config.listen.iter().map(ToString::to_string).collect()
     };
 
-    let mut bus = bus::Bus::for_address(address.as_deref()).await?;
+    let systemd_fds = systemd_listen_fds()?;
+
+    // `bus::Bus::for_systemd_fds` lives in the `busd` library crate, not this binary; this
+    // checkout has no `Cargo.toml` or `src/lib.rs` for it (or any crate manifest declaring
+    // `socket2` as a dependency), so there's no library-side diff to show here.
+    let mut bus = if args.systemd || !systemd_fds.is_empty() {
+        bus::Bus::for_systemd_fds(systemd_fds).await?
+    } else {
+        bus::Bus::for_addresses(&addresses).await?
+    };
 
     if let Some(fd) = args.ready_fd {
         // SAFETY: We don't have any way to know if the fd is valid or not. The parent process is
@@ -101,31 +378,165 @@ async fn main() -> Result<()> {
         ready_file.write_all(b"READY=1\n")?;
     }
 
+    let notifier = Notifier::from_env()?.map(Arc::new);
+    if let Some(notifier) = &notifier {
+        notifier.notify("READY=1");
+        notifier.notify(&format!(
+            "STATUS=Processing requests ({} connections)",
+            bus.connection_count()
+        ));
+        spawn_watchdog(Arc::clone(notifier));
+    }
+
     if args.print_address {
         println!("{}", bus.address());
     }
 
-    let command_future = run_command(args.command, bus.address().to_string());
+    let has_command = args.command.is_some();
+    let mut command_future: Pin<
+        Box<dyn std::future::Future<Output = Result<std::process::ExitStatus>>>,
+    > = Box::pin(run_command(args.command, bus.address().to_string()));
+
+    let idle_timeout = if args.exit_with_session {
+        Some(Duration::ZERO)
+    } else {
+        args.idle_timeout.map(Duration::from_secs)
+    };
+    let mut command_exited = !has_command;
+    let mut command_exit_code: Option<i32> = None;
+    let mut idle_since: Option<Instant> = None;
+    // Also used to periodically refresh the sd_notify STATUS line with the live connection count.
+    let mut status_check = tokio::time::interval(Duration::from_secs(1));
 
     let mut sig_int = tokio::signal::unix::signal(SignalKind::interrupt())?;
+    let mut sig_term = tokio::signal::unix::signal(SignalKind::terminate())?;
+    let mut sig_hup = tokio::signal::unix::signal(SignalKind::hangup())?;
+
+    {
+        // Pinned once and only ever polled, never rebuilt: reconstructing this future on every
+        // loop iteration would drop the in-flight accept loop (and thus connections) every time a
+        // different `select!` arm resolves (e.g. a SIGHUP or a status tick). Scoped to this block
+        // so the borrow of `bus` it holds ends before `bus.cleanup()` runs below.
+        let bus_run = bus.run();
+        tokio::pin!(bus_run);
 
-    select! {
-        _ = sig_int.recv() => {
-            info!("Received SIGINT, shutting down..");
-        },
-        res = bus.run() => match res {
-            Ok(()) => warn!("Bus stopped, shutting down.."),
-            Err(e) => error!("Bus stopped with an error: {}", e),
-        },
-        res = command_future => match res {
-            Ok(()) => info!("Command exited, shutting down.."),
-            Err(err) => error!("Command exited with an error: {}", err),
+        loop {
+            select! {
+                _ = sig_int.recv() => {
+                    info!("Received SIGINT, shutting down..");
+                    break;
+                },
+                _ = sig_term.recv() => {
+                    info!("Received SIGTERM, shutting down..");
+                    break;
+                },
+                _ = status_check.tick(), if notifier.is_some() || idle_timeout.is_some() => {
+                    let connections = bus.connection_count();
+
+                    if let Some(notifier) = &notifier {
+                        notifier.notify(&format!(
+                            "STATUS=Processing requests ({connections} connections)"
+                        ));
+                    }
+
+                    if let Some(timeout) = idle_timeout {
+                        if connections == 0 && command_exited {
+                            let since = *idle_since.get_or_insert_with(Instant::now);
+                            if since.elapsed() >= timeout {
+                                info!("Bus idle for {:?}, shutting down..", timeout);
+                                break;
+                            }
+                        } else {
+                            idle_since = None;
+                        }
+                    }
+                },
+                _ = sig_hup.recv() => {
+                    // `bus.reload_config` is another busd-library addition (applying a freshly
+                    // read Config to the running Bus) that this checkout has no library source
+                    // tree to show a diff for.
+                    info!("Received SIGHUP, reloading configuration..");
+                    if let Some(notifier) = &notifier {
+                        notifier.notify("RELOADING=1");
+                    }
+
+                    match Config::read_file(&config_path) {
+                        Ok(config) => match bus.reload_config(config).await {
+                            Ok(()) => info!("configuration reloaded"),
+                            Err(e) => error!("failed to apply reloaded configuration: {}", e),
+                        },
+                        Err(e) => error!(
+                            "failed to read configuration file {}: {}",
+                            config_path.display(),
+                            e
+                        ),
+                    }
+
+                    if let Some(notifier) = &notifier {
+                        notifier.notify("READY=1");
+                    }
+                },
+                res = &mut bus_run => {
+                    match res {
+                        Ok(()) => warn!("Bus stopped, shutting down.."),
+                        Err(e) => error!("Bus stopped with an error: {}", e),
+                    }
+                    break;
+                },
+                res = &mut command_future => {
+                    match res {
+                        Ok(status) => {
+                            if status.success() {
+                                info!("Command exited..");
+                            } else {
+                                warn!("Command exited with status {}", status);
+                            }
+                            command_exit_code = Some(exit_code_for_status(status));
+                        }
+                        Err(err) => {
+                            error!("Command exited with an error: {}", err);
+                            command_exit_code = Some(1);
+                        }
+                    }
+                    command_exited = true;
+
+                    let Some(timeout) = idle_timeout else {
+                        break;
+                    };
+
+                    // Check idle state right away instead of waiting for the next
+                    // `status_check` tick, so a zero `--idle-timeout` (i.e.
+                    // `--exit-with-session`) exits immediately once the command has exited and
+                    // the bus is already idle.
+                    if bus.connection_count() == 0 {
+                        let since = *idle_since.get_or_insert_with(Instant::now);
+                        if since.elapsed() >= timeout {
+                            info!("Bus idle for {:?}, shutting down..", timeout);
+                            break;
+                        }
+                    } else {
+                        idle_since = None;
+                    }
+
+                    // Don't poll the already-completed future again; wait for the bus to go idle.
+                    command_future = Box::pin(std::future::pending());
+                }
+            }
         }
     }
 
+    if let Some(notifier) = &notifier {
+        notifier.notify("STOPPING=1");
+    }
+
     if let Err(e) = bus.cleanup().await {
         error!("Failed to clean up: {}", e);
     }
 
+    // Surface the command's exit status as our own, so supervisors see the real result.
+    if let Some(code) = command_exit_code {
+        std::process::exit(code);
+    }
+
     Ok(())
 }